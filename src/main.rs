@@ -157,17 +157,16 @@ fn main() {
             ],
             min_weight_for_consensus: 0,
             expiry_date: time::now() + time::Duration::days(36524),
+            permissions: Vec::new(),
+            mutable_attributes_revision: 0,
             data: vec!['D' as u8, 'E' as u8, 'F' as u8]
         },
         versions: vec![
-            Version {
-                index: 0,
-                data: vec!['v' as u8, '0' as u8]
-            }
+            Version::new(0, vec!['v' as u8, '0' as u8])
         ]
     };
     println!("user_session_packet:\n{:?}", user_session_packet);
 
-    user_session_packet.versions.push(Version{ index: 1, data: vec!['v' as u8, '1' as u8] });
+    user_session_packet.versions.push(Version::new(1, vec!['v' as u8, '1' as u8]));
     println!("user_session_packet:\n{:?}", user_session_packet);
 }
@@ -1,6 +1,10 @@
+use std::cmp;
+use std::error;
 use std::fmt;
 
 use routing::NameType;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::hash::sha512;
 use sodiumoxide::crypto::sign;
 use time;
 
@@ -62,10 +66,87 @@ pub struct MutableAttributes {
     /// Coarse-grained expiry date around which time the piece of `Data` will be removed from the
     /// network.
     pub expiry_date: time::Tm,
+    /// Fine-grained per-action permissions, checked before falling back to membership of
+    /// `owner_keys` (weights and `min_weight_for_consensus` are not consulted).  See `is_allowed`.
+    pub permissions: Vec<(User, Vec<(Action, Permission)>)>,
+    /// Incremented whenever `owner_keys`, `min_weight_for_consensus`, `permissions` or
+    /// `expiry_date` change.  Left untouched by changes to `data` alone, so that
+    /// `Data::metadata_changed_since` can detect a metadata-only mutation (e.g. a delete) without
+    /// it being confused for, or requiring, a new content `Version`.
+    pub mutable_attributes_revision: u64,
     /// Arbitrary, mutable, `Data`-wide information.  May be empty.
     pub data: Vec<u8>,
 }
 
+/// An operation which can be performed against a piece of `Data`, used as the key of the
+/// fine-grained permissions table in `MutableAttributes::permissions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Insert a new `Version`.
+    Insert,
+    /// Update `MutableAttributes`, or overwrite an existing `Version`.
+    Update,
+    /// Remove a `Version`.
+    Delete,
+    /// Change the `permissions` table itself, or the `owner_keys`/`min_weight_for_consensus` it
+    /// falls back to.
+    ManagePermissions,
+}
+
+/// Identifies who a `Permission` entry in `MutableAttributes::permissions` applies to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum User {
+    /// Applies to any signing key not covered by a more specific `User::Key` entry.
+    Anyone,
+    /// Applies only to the holder of this specific public key.
+    Key(sign::PublicKey),
+}
+
+/// Whether a `User` is allowed or denied a given `Action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// The action is permitted.
+    Allow,
+    /// The action is forbidden, overriding any less specific `Allow` for the same `User`.
+    Deny,
+}
+
+impl MutableAttributes {
+    /// Returns whether `key` is permitted to perform `action` against this `Data` instance.
+    ///
+    /// An explicit `permissions` entry for `User::Key(key)` takes precedence over any
+    /// `User::Anyone` entry; within a matching entry, `Permission::Deny` takes precedence over
+    /// `Permission::Allow`.  If no entry matches at all, `key` is allowed iff it is one of the
+    /// current `owner_keys` (owners implicitly retain every right).
+    pub fn is_allowed(&self, key: &sign::PublicKey, action: Action) -> bool {
+        if let Some(permission) = self.lookup_permission(&User::Key(key.clone()), action) {
+            return permission == Permission::Allow;
+        }
+        if let Some(permission) = self.lookup_permission(&User::Anyone, action) {
+            return permission == Permission::Allow;
+        }
+        self.owner_keys.iter().any(|owner| &owner.key == key)
+    }
+
+    fn lookup_permission(&self, user: &User, action: Action) -> Option<Permission> {
+        self.permissions
+            .iter()
+            .find(|&&(ref candidate, _)| candidate == user)
+            .and_then(|&(_, ref actions)| {
+                let mut result = None;
+                for &(candidate_action, permission) in actions {
+                    if candidate_action == action {
+                        if permission == Permission::Deny {
+                            return Some(Permission::Deny);
+                        }
+                        result = Some(permission);
+                    }
+                }
+                result
+            })
+    }
+}
+
 /// A representation of a single version.  The `index` allows provision of strict total ordering of
 /// the `Version`s.  It can also hold arbitrary data specific to that particular `Version`, e.g.
 /// encrypted content or the name of a piece of "Immutable Data".
@@ -73,6 +154,1086 @@ pub struct MutableAttributes {
 pub struct Version {
     /// Sequential number to provide strict total order of versions.
     pub index: u64,
+    /// sha256 hash of `data`, letting managing nodes distinguish a genuine content change from a
+    /// metadata-only mutation without comparing the (possibly large) `data` payloads directly.
+    pub content_hash: [u8; 32],
     /// Arbitrary, version-specific information.  May be empty.
     pub data: Vec<u8>,
 }
+
+impl Version {
+    /// Creates a new `Version` with `index`, computing `content_hash` as the sha256 of `data`.
+    pub fn new(index: u64, data: Vec<u8>) -> Version {
+        let content_hash = sha256::hash(&data).0;
+        Version {
+            index: index,
+            content_hash: content_hash,
+            data: data,
+        }
+    }
+}
+
+
+
+// --------------------------------------------------------------------------------------------
+// Serialisation
+// --------------------------------------------------------------------------------------------
+
+/// Maximum number of elements permitted in any length-prefixed collection (owner keys, versions,
+/// and the `data` blobs).  This guards against a corrupt or malicious over-long length prefix
+/// claiming a collection far larger than could plausibly have been serialised, regardless of how
+/// large the supplied buffer happens to be.
+const MAX_COLLECTION_LEN: u64 = 1 << 32;
+
+/// Errors which can occur while serialising or deserialising a `Data` instance.
+#[derive(Debug)]
+pub enum SerialisationError {
+    /// The buffer ended before all expected fields could be read.
+    UnexpectedEof,
+    /// A length prefix claimed an implausibly large element count.
+    LengthPrefixTooLarge(u64),
+    /// A tagged field (e.g. a `User`, `Action` or `Permission`) held an unrecognised tag byte.
+    InvalidTag(u8),
+    /// The buffer's `schema_version` header did not match any schema version this build knows how
+    /// to read or upgrade from.
+    UnsupportedSchemaVersion(u16),
+}
+
+impl fmt::Display for SerialisationError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SerialisationError::UnexpectedEof => {
+                write!(formatter, "buffer ended before all fields could be read")
+            }
+            SerialisationError::LengthPrefixTooLarge(length) => {
+                write!(formatter, "length prefix of {} exceeds the permitted maximum", length)
+            }
+            SerialisationError::InvalidTag(tag) => {
+                write!(formatter, "unrecognised tag byte {}", tag)
+            }
+            SerialisationError::UnsupportedSchemaVersion(version) => {
+                write!(formatter, "unsupported schema version {}", version)
+            }
+        }
+    }
+}
+
+impl error::Error for SerialisationError {
+    fn description(&self) -> &str {
+        match *self {
+            SerialisationError::UnexpectedEof => "unexpected end of buffer",
+            SerialisationError::LengthPrefixTooLarge(_) => "length prefix too large",
+            SerialisationError::InvalidTag(_) => "invalid tag byte",
+            SerialisationError::UnsupportedSchemaVersion(_) => "unsupported schema version",
+        }
+    }
+}
+
+fn write_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.push((value & 0xff) as u8);
+    buffer.push(((value >> 8) & 0xff) as u8);
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> Result<(u16, usize), SerialisationError> {
+    if offset + 2 > buffer.len() {
+        return Err(SerialisationError::UnexpectedEof);
+    }
+    let value = (buffer[offset] as u16) | ((buffer[offset + 1] as u16) << 8);
+    Ok((value, offset + 2))
+}
+
+fn write_u64(buffer: &mut Vec<u8>, value: u64) {
+    for index in 0..8 {
+        buffer.push(((value >> (8 * index)) & 0xff) as u8);
+    }
+}
+
+fn write_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    write_u64(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn read_u8(buffer: &[u8], offset: usize) -> Result<(u8, usize), SerialisationError> {
+    if offset + 1 > buffer.len() {
+        return Err(SerialisationError::UnexpectedEof);
+    }
+    Ok((buffer[offset], offset + 1))
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> Result<(u64, usize), SerialisationError> {
+    if offset + 8 > buffer.len() {
+        return Err(SerialisationError::UnexpectedEof);
+    }
+    let mut value = 0u64;
+    for index in 0..8 {
+        value |= (buffer[offset + index] as u64) << (8 * index);
+    }
+    Ok((value, offset + 8))
+}
+
+fn read_fixed(buffer: &[u8], offset: usize, length: usize)
+              -> Result<(&[u8], usize), SerialisationError> {
+    if offset + length > buffer.len() {
+        return Err(SerialisationError::UnexpectedEof);
+    }
+    Ok((&buffer[offset..offset + length], offset + length))
+}
+
+fn read_bytes(buffer: &[u8], offset: usize) -> Result<(&[u8], usize), SerialisationError> {
+    let (length, offset) = try!(read_u64(buffer, offset));
+    if length > MAX_COLLECTION_LEN {
+        return Err(SerialisationError::LengthPrefixTooLarge(length));
+    }
+    read_fixed(buffer, offset, length as usize)
+}
+
+fn copy_to_array(source: &[u8]) -> [u8; 64] {
+    let mut array = [0u8; 64];
+    for (dest, src) in array.iter_mut().zip(source.iter()) {
+        *dest = *src;
+    }
+    array
+}
+
+fn copy_to_32_array(source: &[u8]) -> [u8; 32] {
+    let mut array = [0u8; 32];
+    for (dest, src) in array.iter_mut().zip(source.iter()) {
+        *dest = *src;
+    }
+    array
+}
+
+fn write_user(buffer: &mut Vec<u8>, user: &User) {
+    match *user {
+        User::Anyone => buffer.push(0),
+        User::Key(ref key) => {
+            buffer.push(1);
+            buffer.extend_from_slice(&key.0);
+        }
+    }
+}
+
+fn read_user(buffer: &[u8], offset: usize) -> Result<(User, usize), SerialisationError> {
+    let (tag, offset) = try!(read_u8(buffer, offset));
+    match tag {
+        0 => Ok((User::Anyone, offset)),
+        1 => {
+            let (key_bytes, offset) = try!(read_fixed(buffer, offset, 32));
+            let key = sign::PublicKey::from_slice(key_bytes)
+                          .expect("validated in `ArchivedData::new`");
+            Ok((User::Key(key), offset))
+        }
+        _ => Err(SerialisationError::InvalidTag(tag)),
+    }
+}
+
+fn write_action(buffer: &mut Vec<u8>, action: Action) {
+    buffer.push(match action {
+        Action::Insert => 0,
+        Action::Update => 1,
+        Action::Delete => 2,
+        Action::ManagePermissions => 3,
+    });
+}
+
+fn read_action(buffer: &[u8], offset: usize) -> Result<(Action, usize), SerialisationError> {
+    let (tag, offset) = try!(read_u8(buffer, offset));
+    let action = match tag {
+        0 => Action::Insert,
+        1 => Action::Update,
+        2 => Action::Delete,
+        3 => Action::ManagePermissions,
+        _ => return Err(SerialisationError::InvalidTag(tag)),
+    };
+    Ok((action, offset))
+}
+
+fn write_permission(buffer: &mut Vec<u8>, permission: Permission) {
+    buffer.push(match permission {
+        Permission::Allow => 0,
+        Permission::Deny => 1,
+    });
+}
+
+fn read_permission(buffer: &[u8], offset: usize) -> Result<(Permission, usize), SerialisationError> {
+    let (tag, offset) = try!(read_u8(buffer, offset));
+    let permission = match tag {
+        0 => Permission::Allow,
+        1 => Permission::Deny,
+        _ => return Err(SerialisationError::InvalidTag(tag)),
+    };
+    Ok((permission, offset))
+}
+
+/// A schema version in the `Data` upgrade chain.  `VERSION` is the `u16` written into the
+/// `schema_version` header of the serialised form, and `upgrade` migrates the immediately
+/// preceding version forward by filling in any fields it lacks with sensible defaults.
+pub trait Upgrade: Sized {
+    /// The historical type this schema version is upgraded from.
+    type Previous;
+    /// The `schema_version` header value identifying this schema version on the wire.
+    const VERSION: u16;
+    /// Migrates `prev` forward to this schema version.
+    fn upgrade(prev: Self::Previous) -> Self;
+}
+
+/// Schema version 1 form of `FixedAttributes`, predating `min_retained_count`.
+#[derive(Debug)]
+pub struct FixedAttributesV1 {
+    /// Identifier of the `Data` type.
+    pub type_tag: u64,
+    /// Identity of the piece of `Data`.
+    pub id: NameType,
+    /// Maximum number of versions allowed.
+    pub max_versions: u64,
+    /// Arbitrary, immutable, `Data`-wide information.  May be empty.
+    pub data: Vec<u8>,
+}
+
+/// Schema version 1 and 2 form of `Version`, predating `content_hash`.
+#[derive(Debug)]
+pub struct VersionV2 {
+    /// Sequential number to provide strict total order of versions.
+    pub index: u64,
+    /// Arbitrary, version-specific information.  May be empty.
+    pub data: Vec<u8>,
+}
+
+/// Schema version 1 and 2 form of `MutableAttributes`, predating `mutable_attributes_revision`.
+#[derive(Debug)]
+pub struct MutableAttributesV2 {
+    /// Current owner or owners' public keys.  Cannot be empty.
+    pub owner_keys: Vec<KeyAndWeight>,
+    /// Minimum total weight of signatories' keys to allow a mutation of the piece of `Data`.
+    pub min_weight_for_consensus: u64,
+    /// Coarse-grained expiry date around which time the piece of `Data` will be removed from the
+    /// network.
+    pub expiry_date: time::Tm,
+    /// Fine-grained per-action permissions.  See `MutableAttributes::permissions`.
+    pub permissions: Vec<(User, Vec<(Action, Permission)>)>,
+    /// Arbitrary, mutable, `Data`-wide information.  May be empty.
+    pub data: Vec<u8>,
+}
+
+/// Schema version 1 form of `Data`, predating `FixedAttributes::min_retained_count`.  Retained
+/// solely so that packets serialised by older code can still be read; see `Data::deserialise`.
+#[derive(Debug)]
+pub struct DataV1 {
+    /// Immutable attributes which apply to the entire `Data` instance.
+    pub fixed_attributes: FixedAttributesV1,
+    /// Attributes which apply to the entire `Data` instance, but which can be changed with proper
+    /// authorisation.
+    pub mutable_attributes: MutableAttributesV2,
+    /// The most recent (which could encompass all) versions of the `Data` instance.
+    pub versions: Vec<VersionV2>,
+}
+
+/// Schema version 2 form of `Data`, predating `Version::content_hash` and
+/// `MutableAttributes::mutable_attributes_revision`.  Retained solely so that packets serialised
+/// by older code can still be read; see `Data::deserialise`.
+#[derive(Debug)]
+pub struct DataV2 {
+    /// Immutable attributes which apply to the entire `Data` instance.
+    pub fixed_attributes: FixedAttributes,
+    /// Attributes which apply to the entire `Data` instance, but which can be changed with proper
+    /// authorisation.
+    pub mutable_attributes: MutableAttributesV2,
+    /// The most recent (which could encompass all) versions of the `Data` instance.
+    pub versions: Vec<VersionV2>,
+}
+
+impl Upgrade for DataV2 {
+    type Previous = DataV1;
+    const VERSION: u16 = 2;
+
+    fn upgrade(prev: DataV1) -> DataV2 {
+        DataV2 {
+            fixed_attributes: FixedAttributes {
+                type_tag: prev.fixed_attributes.type_tag,
+                id: prev.fixed_attributes.id,
+                max_versions: prev.fixed_attributes.max_versions,
+                // Archive down to the single most recent version unless told otherwise.
+                min_retained_count: 1,
+                data: prev.fixed_attributes.data,
+            },
+            mutable_attributes: prev.mutable_attributes,
+            versions: prev.versions,
+        }
+    }
+}
+
+impl Upgrade for Data {
+    type Previous = DataV2;
+    const VERSION: u16 = 3;
+
+    fn upgrade(prev: DataV2) -> Data {
+        Data {
+            fixed_attributes: prev.fixed_attributes,
+            mutable_attributes: MutableAttributes {
+                owner_keys: prev.mutable_attributes.owner_keys,
+                min_weight_for_consensus: prev.mutable_attributes.min_weight_for_consensus,
+                expiry_date: prev.mutable_attributes.expiry_date,
+                permissions: prev.mutable_attributes.permissions,
+                // No mutation history to have incremented a revision counter yet.
+                mutable_attributes_revision: 0,
+                data: prev.mutable_attributes.data,
+            },
+            versions: prev.versions
+                          .into_iter()
+                          .map(|version| Version::new(version.index, version.data))
+                          .collect(),
+        }
+    }
+}
+
+/// Parses the part of the schema version 1/2 wire format which is common to both: everything
+/// from `owner_keys` to the end of `versions`.  `offset` must point immediately after the fixed
+/// attributes' `data` blob.
+fn parse_v2_tail(buffer: &[u8],
+                  offset: usize)
+                  -> Result<(MutableAttributesV2, Vec<VersionV2>), SerialisationError> {
+    let (owner_keys_count, mut offset) = try!(read_u64(buffer, offset));
+    if owner_keys_count > MAX_COLLECTION_LEN {
+        return Err(SerialisationError::LengthPrefixTooLarge(owner_keys_count));
+    }
+    let mut owner_keys = Vec::with_capacity(owner_keys_count as usize);
+    for _ in 0..owner_keys_count {
+        let (key_bytes, new_offset) = try!(read_fixed(buffer, offset, 32));
+        let key = sign::PublicKey::from_slice(key_bytes).expect("validated above");
+        let (weight, new_offset) = try!(read_u64(buffer, new_offset));
+        owner_keys.push(KeyAndWeight {
+            key: key,
+            weight: weight,
+        });
+        offset = new_offset;
+    }
+
+    let (permissions_count, mut offset) = try!(read_u64(buffer, offset));
+    if permissions_count > MAX_COLLECTION_LEN {
+        return Err(SerialisationError::LengthPrefixTooLarge(permissions_count));
+    }
+    let mut permissions = Vec::with_capacity(permissions_count as usize);
+    for _ in 0..permissions_count {
+        let (user, new_offset) = try!(read_user(buffer, offset));
+        let (actions_count, new_offset) = try!(read_u64(buffer, new_offset));
+        if actions_count > MAX_COLLECTION_LEN {
+            return Err(SerialisationError::LengthPrefixTooLarge(actions_count));
+        }
+        let mut actions = Vec::with_capacity(actions_count as usize);
+        let mut action_offset = new_offset;
+        for _ in 0..actions_count {
+            let (action, next_offset) = try!(read_action(buffer, action_offset));
+            let (permission, next_offset) = try!(read_permission(buffer, next_offset));
+            actions.push((action, permission));
+            action_offset = next_offset;
+        }
+        permissions.push((user, actions));
+        offset = action_offset;
+    }
+
+    let (min_weight_for_consensus, offset) = try!(read_u64(buffer, offset));
+    let (expiry_sec, offset) = try!(read_u64(buffer, offset));
+    let (expiry_nsec, offset) = try!(read_u64(buffer, offset));
+    let expiry_date = time::at_utc(time::Timespec::new(expiry_sec as i64, expiry_nsec as i32));
+    let (mutable_data, offset) = try!(read_bytes(buffer, offset));
+
+    let (versions_count, mut offset) = try!(read_u64(buffer, offset));
+    if versions_count > MAX_COLLECTION_LEN {
+        return Err(SerialisationError::LengthPrefixTooLarge(versions_count));
+    }
+    let mut versions = Vec::with_capacity(versions_count as usize);
+    for _ in 0..versions_count {
+        let (index, new_offset) = try!(read_u64(buffer, offset));
+        let (data, new_offset) = try!(read_bytes(buffer, new_offset));
+        versions.push(VersionV2 {
+            index: index,
+            data: data.to_vec(),
+        });
+        offset = new_offset;
+    }
+    let _ = offset;
+
+    Ok((MutableAttributesV2 {
+            owner_keys: owner_keys,
+            min_weight_for_consensus: min_weight_for_consensus,
+            expiry_date: expiry_date,
+            permissions: permissions,
+            data: mutable_data.to_vec(),
+        },
+        versions))
+}
+
+fn deserialise_v1(buffer: &[u8]) -> Result<DataV1, SerialisationError> {
+    let (_schema_version, offset) = try!(read_u16(buffer, 0));
+    let (type_tag, offset) = try!(read_u64(buffer, offset));
+    let (id_bytes, offset) = try!(read_fixed(buffer, offset, 64));
+    let id = NameType::new(copy_to_array(id_bytes));
+    let (max_versions, offset) = try!(read_u64(buffer, offset));
+    let (fixed_data, offset) = try!(read_bytes(buffer, offset));
+
+    let (mutable_attributes, versions) = try!(parse_v2_tail(buffer, offset));
+
+    Ok(DataV1 {
+        fixed_attributes: FixedAttributesV1 {
+            type_tag: type_tag,
+            id: id,
+            max_versions: max_versions,
+            data: fixed_data.to_vec(),
+        },
+        mutable_attributes: mutable_attributes,
+        versions: versions,
+    })
+}
+
+fn deserialise_v2(buffer: &[u8]) -> Result<DataV2, SerialisationError> {
+    let (_schema_version, offset) = try!(read_u16(buffer, 0));
+    let (type_tag, offset) = try!(read_u64(buffer, offset));
+    let (id_bytes, offset) = try!(read_fixed(buffer, offset, 64));
+    let id = NameType::new(copy_to_array(id_bytes));
+    let (max_versions, offset) = try!(read_u64(buffer, offset));
+    let (min_retained_count, offset) = try!(read_u8(buffer, offset));
+    let (fixed_data, offset) = try!(read_bytes(buffer, offset));
+
+    let (mutable_attributes, versions) = try!(parse_v2_tail(buffer, offset));
+
+    Ok(DataV2 {
+        fixed_attributes: FixedAttributes {
+            type_tag: type_tag,
+            id: id,
+            max_versions: max_versions,
+            min_retained_count: min_retained_count,
+            data: fixed_data.to_vec(),
+        },
+        mutable_attributes: mutable_attributes,
+        versions: versions,
+    })
+}
+
+impl Data {
+    /// Serialises this `Data` instance into a stable, little-endian binary format suitable for
+    /// storage or transmission over the network.
+    ///
+    /// The buffer is prefixed with a `u16` `schema_version` (currently `Data::VERSION`), followed
+    /// by the fields themselves.  Each variable-length element (the three `data` blobs, plus the
+    /// `owner_keys`, `permissions` and `versions` vectors and each permissions entry's list of
+    /// `(Action, Permission)` pairs) is preceded by a `u64` giving its length or element count.
+    /// Each `User` is a one-byte tag, followed by a 32-byte key for `User::Key`; all other fields
+    /// are fixed-width.
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        write_u16(&mut buffer, Data::VERSION);
+        write_u64(&mut buffer, self.fixed_attributes.type_tag);
+        buffer.extend_from_slice(&self.fixed_attributes.id.0);
+        write_u64(&mut buffer, self.fixed_attributes.max_versions);
+        buffer.push(self.fixed_attributes.min_retained_count);
+        write_bytes(&mut buffer, &self.fixed_attributes.data);
+
+        write_u64(&mut buffer, self.mutable_attributes.owner_keys.len() as u64);
+        for key_and_weight in &self.mutable_attributes.owner_keys {
+            buffer.extend_from_slice(&(key_and_weight.key).0);
+            write_u64(&mut buffer, key_and_weight.weight);
+        }
+        write_u64(&mut buffer, self.mutable_attributes.permissions.len() as u64);
+        for &(ref user, ref actions) in &self.mutable_attributes.permissions {
+            write_user(&mut buffer, user);
+            write_u64(&mut buffer, actions.len() as u64);
+            for &(action, permission) in actions {
+                write_action(&mut buffer, action);
+                write_permission(&mut buffer, permission);
+            }
+        }
+        write_u64(&mut buffer, self.mutable_attributes.min_weight_for_consensus);
+        let expiry = self.mutable_attributes.expiry_date.to_timespec();
+        write_u64(&mut buffer, expiry.sec as u64);
+        write_u64(&mut buffer, expiry.nsec as u64);
+        write_u64(&mut buffer, self.mutable_attributes.mutable_attributes_revision);
+        write_bytes(&mut buffer, &self.mutable_attributes.data);
+
+        write_u64(&mut buffer, self.versions.len() as u64);
+        for version in &self.versions {
+            write_u64(&mut buffer, version.index);
+            buffer.extend_from_slice(&version.content_hash);
+            write_bytes(&mut buffer, &version.data);
+        }
+
+        buffer
+    }
+
+    /// Deserialises a `Data` instance previously produced by `serialise`, allocating fresh storage
+    /// for every field.  Reads the `schema_version` header and, if it names an older schema,
+    /// upgrades it forward through the registered `Upgrade` chain to the current `Data` layout.
+    ///
+    /// Prefer `ArchivedData::new` when the caller only needs to inspect a handful of fields of a
+    /// packet already known to be at the current schema version (e.g. a managing node checking
+    /// `expiry_date` before deciding whether to store the packet), since that allocates only for
+    /// the permissions table.
+    pub fn deserialise(buffer: &[u8]) -> Result<Data, SerialisationError> {
+        let (schema_version, _) = try!(read_u16(buffer, 0));
+        match schema_version {
+            1 => {
+                let data_v1 = try!(deserialise_v1(buffer));
+                Ok(Data::upgrade(DataV2::upgrade(data_v1)))
+            }
+            2 => {
+                let data_v2 = try!(deserialise_v2(buffer));
+                Ok(Data::upgrade(data_v2))
+            }
+            version if version == Data::VERSION => {
+                let archived = try!(ArchivedData::new(buffer));
+                Ok(archived.to_owned())
+            }
+            version => Err(SerialisationError::UnsupportedSchemaVersion(version)),
+        }
+    }
+
+    /// Appends `version` to `versions`, enforcing the strict increment-by-one `index` policy
+    /// described in the module docs: `version.index` must be exactly one greater than the most
+    /// recent version's (or `0` if this is the first version), otherwise it is rejected.  Then
+    /// triggers `enforce_version_limit`, so a non-`None` result means an archive was created.
+    pub fn push_version(&mut self, version: Version) -> Result<Option<(Vec<u8>, NameType)>, VersionError> {
+        let expected_index = self.versions.last().map_or(0, |last| last.index + 1);
+        if version.index != expected_index {
+            return Err(VersionError::OutOfSequence {
+                expected: expected_index,
+                actual: version.index,
+            });
+        }
+        self.versions.push(version);
+        Ok(self.enforce_version_limit())
+    }
+
+    /// If `versions.len()` exceeds `max_versions`, strips the lowest-`index` versions from the
+    /// front of `versions` down to `min_retained_count` (clamped to a minimum of 1).  The removed
+    /// `Version`s are serialised into an archive blob, and a deterministic `NameType` is derived by
+    /// hashing that blob, so the pair can be stored as `ImmutableData` and later retrieved by name.
+    /// Returns `None` if no archiving was necessary.
+    pub fn enforce_version_limit(&mut self) -> Option<(Vec<u8>, NameType)> {
+        if (self.versions.len() as u64) <= self.fixed_attributes.max_versions {
+            return None;
+        }
+
+        let min_retained_count = cmp::max(self.fixed_attributes.min_retained_count as usize, 1);
+        let archive_up_to = self.versions.len().saturating_sub(min_retained_count);
+        if archive_up_to == 0 {
+            return None;
+        }
+
+        let archived_versions: Vec<Version> = self.versions.drain(..archive_up_to).collect();
+
+        let mut archive = Vec::new();
+        write_u64(&mut archive, archived_versions.len() as u64);
+        for version in &archived_versions {
+            write_u64(&mut archive, version.index);
+            archive.extend_from_slice(&version.content_hash);
+            write_bytes(&mut archive, &version.data);
+        }
+
+        let name = NameType::new(sha512::hash(&archive).0);
+        Some((archive, name))
+    }
+
+    /// Returns whether any version's content actually changed between `other` and `self`, i.e.
+    /// there's a version index present in one but not the other, or a shared index whose
+    /// `content_hash` differs.  Lets a managing node refreshing a blob after churn detect
+    /// identical content (dedup) rather than assuming every refresh is a genuine new version.
+    pub fn content_changed_since(&self, other: &Data) -> bool {
+        if self.versions.len() != other.versions.len() {
+            return true;
+        }
+        self.versions
+            .iter()
+            .zip(other.versions.iter())
+            .any(|(mine, theirs)| {
+                mine.index != theirs.index || mine.content_hash != theirs.content_hash
+            })
+    }
+
+    /// Returns whether the mutable metadata (owner keys, weights, permissions or expiry) changed
+    /// between `other` and `self`, as tracked by `MutableAttributes::mutable_attributes_revision`.
+    /// This lets e.g. a delete be recorded as a metadata-only transition without it requiring, or
+    /// being confused for, a genuine new content `Version`.
+    pub fn metadata_changed_since(&self, other: &Data) -> bool {
+        self.mutable_attributes.mutable_attributes_revision !=
+        other.mutable_attributes.mutable_attributes_revision
+    }
+}
+
+/// Errors which can occur when appending a new `Version` via `Data::push_version`.
+#[derive(Debug)]
+pub enum VersionError {
+    /// The new version's `index` did not immediately follow the most recent version's `index`.
+    OutOfSequence {
+        /// The `index` that would have continued the sequence.
+        expected: u64,
+        /// The `index` actually supplied.
+        actual: u64,
+    },
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VersionError::OutOfSequence { expected, actual } => {
+                write!(formatter,
+                       "expected next version index {}, but got {}",
+                       expected,
+                       actual)
+            }
+        }
+    }
+}
+
+impl error::Error for VersionError {
+    fn description(&self) -> &str {
+        match *self {
+            VersionError::OutOfSequence { .. } => "version index out of sequence",
+        }
+    }
+}
+
+/// A validated, zero-copy view onto a serialised `Data` instance.
+///
+/// `ArchivedData::new` makes a single pass over `buffer`, checking that every length prefix stays
+/// within bounds.  It copies nothing except the permissions table, which it parses into an owned
+/// `Vec`.  The remaining accessor methods read their fields directly out of
+/// the borrowed buffer on demand, so a managing node can cheaply inspect e.g. `expiry_date` or the
+/// owner weights of a received packet without deserialising the whole `Data`.
+pub struct ArchivedData<'a> {
+    buffer: &'a [u8],
+    id_offset: usize,
+    max_versions_offset: usize,
+    min_retained_count_offset: usize,
+    fixed_data_offset: usize,
+    fixed_data_len: usize,
+    owner_keys_offset: usize,
+    owner_keys_count: usize,
+    permissions: Vec<(User, Vec<(Action, Permission)>)>,
+    min_weight_offset: usize,
+    expiry_offset: usize,
+    revision_offset: usize,
+    mutable_data_offset: usize,
+    mutable_data_len: usize,
+    versions_offset: usize,
+    versions_count: usize,
+}
+
+impl<'a> ArchivedData<'a> {
+    /// Validates `buffer` as a serialised `Data` instance and returns a zero-copy view onto it.
+    /// Returns an error if `buffer` is truncated or contains an over-long length prefix.
+    pub fn new(buffer: &'a [u8]) -> Result<ArchivedData<'a>, SerialisationError> {
+        let (schema_version, offset) = try!(read_u16(buffer, 0));
+        if schema_version != Data::VERSION {
+            return Err(SerialisationError::UnsupportedSchemaVersion(schema_version));
+        }
+        let (_type_tag, offset) = try!(read_u64(buffer, offset));
+        let id_offset = offset;
+        let (_, offset) = try!(read_fixed(buffer, offset, 64));
+        let max_versions_offset = offset;
+        let (_, offset) = try!(read_u64(buffer, offset));
+        let min_retained_count_offset = offset;
+        let (_, offset) = try!(read_u8(buffer, offset));
+        let (fixed_data, offset) = try!(read_bytes(buffer, offset));
+        let fixed_data_offset = offset - fixed_data.len();
+        let fixed_data_len = fixed_data.len();
+
+        let (owner_keys_count, mut offset) = try!(read_u64(buffer, offset));
+        if owner_keys_count > MAX_COLLECTION_LEN {
+            return Err(SerialisationError::LengthPrefixTooLarge(owner_keys_count));
+        }
+        let owner_keys_offset = offset;
+        for _ in 0..owner_keys_count {
+            let (_, new_offset) = try!(read_fixed(buffer, offset, 32));
+            let (_, new_offset) = try!(read_u64(buffer, new_offset));
+            offset = new_offset;
+        }
+
+        let (permissions_count, mut offset) = try!(read_u64(buffer, offset));
+        if permissions_count > MAX_COLLECTION_LEN {
+            return Err(SerialisationError::LengthPrefixTooLarge(permissions_count));
+        }
+        let mut permissions = Vec::with_capacity(permissions_count as usize);
+        for _ in 0..permissions_count {
+            let (user, new_offset) = try!(read_user(buffer, offset));
+            let (actions_count, new_offset) = try!(read_u64(buffer, new_offset));
+            if actions_count > MAX_COLLECTION_LEN {
+                return Err(SerialisationError::LengthPrefixTooLarge(actions_count));
+            }
+            let mut actions = Vec::with_capacity(actions_count as usize);
+            let mut action_offset = new_offset;
+            for _ in 0..actions_count {
+                let (action, next_offset) = try!(read_action(buffer, action_offset));
+                let (permission, next_offset) = try!(read_permission(buffer, next_offset));
+                actions.push((action, permission));
+                action_offset = next_offset;
+            }
+            permissions.push((user, actions));
+            offset = action_offset;
+        }
+
+        let min_weight_offset = offset;
+        let (_, offset) = try!(read_u64(buffer, offset));
+        let expiry_offset = offset;
+        let (_, offset) = try!(read_u64(buffer, offset));
+        let (_, offset) = try!(read_u64(buffer, offset));
+        let revision_offset = offset;
+        let (_, offset) = try!(read_u64(buffer, offset));
+        let (mutable_data, offset) = try!(read_bytes(buffer, offset));
+        let mutable_data_offset = offset - mutable_data.len();
+        let mutable_data_len = mutable_data.len();
+
+        let (versions_count, mut offset) = try!(read_u64(buffer, offset));
+        if versions_count > MAX_COLLECTION_LEN {
+            return Err(SerialisationError::LengthPrefixTooLarge(versions_count));
+        }
+        let versions_offset = offset;
+        for _ in 0..versions_count {
+            let (_, new_offset) = try!(read_u64(buffer, offset));
+            let (_, new_offset) = try!(read_fixed(buffer, new_offset, 32));
+            let (_, new_offset) = try!(read_bytes(buffer, new_offset));
+            offset = new_offset;
+        }
+
+        Ok(ArchivedData {
+            buffer: buffer,
+            id_offset: id_offset,
+            max_versions_offset: max_versions_offset,
+            min_retained_count_offset: min_retained_count_offset,
+            fixed_data_offset: fixed_data_offset,
+            fixed_data_len: fixed_data_len,
+            owner_keys_offset: owner_keys_offset,
+            owner_keys_count: owner_keys_count as usize,
+            permissions: permissions,
+            min_weight_offset: min_weight_offset,
+            expiry_offset: expiry_offset,
+            revision_offset: revision_offset,
+            mutable_data_offset: mutable_data_offset,
+            mutable_data_len: mutable_data_len,
+            versions_offset: versions_offset,
+            versions_count: versions_count as usize,
+        })
+    }
+
+    /// Returns the `type_tag` of the underlying `Data`.
+    pub fn type_tag(&self) -> u64 {
+        read_u64(self.buffer, 2).expect("validated in `ArchivedData::new`").0
+    }
+
+    /// Returns the `id` of the underlying `Data`.
+    pub fn id(&self) -> NameType {
+        NameType::new(copy_to_array(&self.buffer[self.id_offset..self.id_offset + 64]))
+    }
+
+    /// Returns the `max_versions` limit of the underlying `Data`.
+    pub fn max_versions(&self) -> u64 {
+        read_u64(self.buffer, self.max_versions_offset).expect("validated in `ArchivedData::new`").0
+    }
+
+    /// Returns the `min_retained_count` of the underlying `Data`.
+    pub fn min_retained_count(&self) -> u8 {
+        read_u8(self.buffer, self.min_retained_count_offset).expect("validated in `ArchivedData::new`").0
+    }
+
+    /// Returns the `FixedAttributes::data` blob of the underlying `Data`, without allocating.
+    pub fn fixed_data(&self) -> &'a [u8] {
+        &self.buffer[self.fixed_data_offset..self.fixed_data_offset + self.fixed_data_len]
+    }
+
+    /// Returns the weighted owner keys of the underlying `Data`, parsed lazily as the iterator
+    /// advances.
+    pub fn owner_keys(&self) -> ArchivedOwnerKeys<'a> {
+        ArchivedOwnerKeys {
+            buffer: self.buffer,
+            offset: self.owner_keys_offset,
+            remaining: self.owner_keys_count,
+        }
+    }
+
+    /// Returns the fine-grained permissions table of the underlying `Data`.  Unlike `owner_keys`
+    /// and `versions`, this is parsed eagerly in `new` rather than lazily, since entries are
+    /// ragged (each `User` carries its own `Vec` of `(Action, Permission)` pairs).
+    pub fn permissions(&self) -> &[(User, Vec<(Action, Permission)>)] {
+        &self.permissions
+    }
+
+    /// Returns the `min_weight_for_consensus` of the underlying `Data`.
+    pub fn min_weight_for_consensus(&self) -> u64 {
+        read_u64(self.buffer, self.min_weight_offset).expect("validated in `ArchivedData::new`").0
+    }
+
+    /// Returns the approximate expiry date of the underlying `Data`.
+    pub fn expiry_date(&self) -> time::Tm {
+        let (sec, offset) = read_u64(self.buffer, self.expiry_offset)
+                                 .expect("validated in `ArchivedData::new`");
+        let (nsec, _) = read_u64(self.buffer, offset).expect("validated in `ArchivedData::new`");
+        time::at_utc(time::Timespec::new(sec as i64, nsec as i32))
+    }
+
+    /// Returns the `MutableAttributes::mutable_attributes_revision` of the underlying `Data`.
+    pub fn mutable_attributes_revision(&self) -> u64 {
+        read_u64(self.buffer, self.revision_offset).expect("validated in `ArchivedData::new`").0
+    }
+
+    /// Returns the `MutableAttributes::data` blob of the underlying `Data`, without allocating.
+    pub fn mutable_data(&self) -> &'a [u8] {
+        &self.buffer[self.mutable_data_offset..self.mutable_data_offset + self.mutable_data_len]
+    }
+
+    /// Returns the versions of the underlying `Data`, parsed lazily as the iterator advances.
+    pub fn versions(&self) -> ArchivedVersions<'a> {
+        ArchivedVersions {
+            buffer: self.buffer,
+            offset: self.versions_offset,
+            remaining: self.versions_count,
+        }
+    }
+
+    /// Copies every field out of this archived view, producing an owned `Data` instance.
+    pub fn to_owned(&self) -> Data {
+        Data {
+            fixed_attributes: FixedAttributes {
+                type_tag: self.type_tag(),
+                id: self.id(),
+                max_versions: self.max_versions(),
+                min_retained_count: self.min_retained_count(),
+                data: self.fixed_data().to_vec(),
+            },
+            mutable_attributes: MutableAttributes {
+                owner_keys: self.owner_keys().map(|entry| entry.to_owned()).collect(),
+                min_weight_for_consensus: self.min_weight_for_consensus(),
+                expiry_date: self.expiry_date(),
+                permissions: self.permissions.clone(),
+                mutable_attributes_revision: self.mutable_attributes_revision(),
+                data: self.mutable_data().to_vec(),
+            },
+            versions: self.versions().map(|version| version.to_owned()).collect(),
+        }
+    }
+}
+
+/// A zero-copy view onto a single serialised owner key and its weight.
+pub struct ArchivedKeyAndWeight<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ArchivedKeyAndWeight<'a> {
+    /// Returns the owner's public key.
+    pub fn key(&self) -> sign::PublicKey {
+        sign::PublicKey::from_slice(&self.buffer[self.offset..self.offset + 32])
+            .expect("validated in `ArchivedData::new`")
+    }
+
+    /// Returns the weight given to this owner's key.
+    pub fn weight(&self) -> u64 {
+        read_u64(self.buffer, self.offset + 32).expect("validated in `ArchivedData::new`").0
+    }
+
+    fn to_owned(&self) -> KeyAndWeight {
+        KeyAndWeight {
+            key: self.key(),
+            weight: self.weight(),
+        }
+    }
+}
+
+/// Lazily parses each serialised owner key and weight as the iterator advances.
+pub struct ArchivedOwnerKeys<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for ArchivedOwnerKeys<'a> {
+    type Item = ArchivedKeyAndWeight<'a>;
+
+    fn next(&mut self) -> Option<ArchivedKeyAndWeight<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let entry = ArchivedKeyAndWeight {
+            buffer: self.buffer,
+            offset: self.offset,
+        };
+        self.offset += 40; // 32-byte key + 8-byte weight
+        self.remaining -= 1;
+        Some(entry)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+
+
+// --------------------------------------------------------------------------------------------
+// Authorisation
+// --------------------------------------------------------------------------------------------
+
+/// Errors which can occur while checking whether a mutation is authorised, as returned by
+/// `MutableAttributes::authorise_mutation`.
+#[derive(Debug)]
+pub enum AuthError {
+    /// None of the supplied public keys matched a current owner key.
+    UnknownSigner,
+    /// At least one supplied key matched an owner, but none of the supplied signatures verified.
+    NoValidSignature,
+    /// At least one signature verified, but the accumulated weight of the verified owners fell
+    /// short of `min_weight_for_consensus`.
+    InsufficientWeight {
+        /// The total weight achieved by the verified signatures.
+        achieved: u64,
+        /// The minimum weight required for consensus.
+        required: u64,
+    },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::UnknownSigner => {
+                write!(formatter, "none of the supplied keys are current owners")
+            }
+            AuthError::NoValidSignature => {
+                write!(formatter, "none of the supplied signatures verified")
+            }
+            AuthError::InsufficientWeight { achieved, required } => {
+                write!(formatter,
+                       "achieved weight {} is below the required {}",
+                       achieved,
+                       required)
+            }
+        }
+    }
+}
+
+impl error::Error for AuthError {
+    fn description(&self) -> &str {
+        match *self {
+            AuthError::UnknownSigner => "unknown signer",
+            AuthError::NoValidSignature => "no valid signature",
+            AuthError::InsufficientWeight { .. } => "insufficient weight",
+        }
+    }
+}
+
+impl MutableAttributes {
+    /// Checks whether `signatures` authorise a mutation of `payload` under this `Data` instance's
+    /// weighted-signature consensus rule (see the module-level docs).
+    ///
+    /// For each supplied pair, the corresponding `KeyAndWeight` is looked up in `owner_keys` (keys
+    /// which aren't current owners are ignored, and a given owner's key is only ever counted once,
+    /// even if it appears more than once in `signatures`) and the signature is verified over
+    /// `payload`.  The mutation is authorised iff at least one signature verifies and the summed
+    /// weight of the verified owners is at least `min_weight_for_consensus`.
+    pub fn authorise_mutation(&self,
+                               signatures: &[(sign::PublicKey, sign::Signature)],
+                               payload: &[u8])
+                               -> Result<(), AuthError> {
+        let mut counted_keys: Vec<&sign::PublicKey> = Vec::new();
+        let mut achieved_weight = 0u64;
+        let mut any_known_signer = false;
+        let mut any_valid_signature = false;
+
+        for &(ref public_key, ref signature) in signatures {
+            let owner = match self.owner_keys.iter().find(|owner| &owner.key == public_key) {
+                Some(owner) => owner,
+                None => continue,
+            };
+            any_known_signer = true;
+
+            if counted_keys.iter().any(|counted_key| *counted_key == public_key) {
+                continue;
+            }
+
+            if sign::verify_detached(signature, payload, public_key) {
+                any_valid_signature = true;
+                counted_keys.push(public_key);
+                achieved_weight += owner.weight;
+            }
+        }
+
+        if any_valid_signature && achieved_weight >= self.min_weight_for_consensus {
+            return Ok(());
+        }
+
+        if !any_known_signer {
+            return Err(AuthError::UnknownSigner);
+        }
+
+        if !any_valid_signature {
+            return Err(AuthError::NoValidSignature);
+        }
+
+        Err(AuthError::InsufficientWeight {
+            achieved: achieved_weight,
+            required: self.min_weight_for_consensus,
+        })
+    }
+}
+
+/// A zero-copy, validated view onto a single serialised `Version`.
+pub struct ArchivedVersion<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ArchivedVersion<'a> {
+    /// Returns the `index` of this version.
+    pub fn index(&self) -> u64 {
+        read_u64(self.buffer, self.offset).expect("validated in `ArchivedData::new`").0
+    }
+
+    /// Returns the `content_hash` of this version, without allocating.
+    pub fn content_hash(&self) -> &'a [u8] {
+        let (_, offset) = read_u64(self.buffer, self.offset).expect("validated in `ArchivedData::new`");
+        read_fixed(self.buffer, offset, 32).expect("validated in `ArchivedData::new`").0
+    }
+
+    /// Returns the `data` of this version, without allocating.
+    pub fn data(&self) -> &'a [u8] {
+        let (_, offset) = read_u64(self.buffer, self.offset).expect("validated in `ArchivedData::new`");
+        let (_, offset) = read_fixed(self.buffer, offset, 32).expect("validated in `ArchivedData::new`");
+        read_bytes(self.buffer, offset).expect("validated in `ArchivedData::new`").0
+    }
+
+    fn to_owned(&self) -> Version {
+        Version {
+            index: self.index(),
+            content_hash: copy_to_32_array(self.content_hash()),
+            data: self.data().to_vec(),
+        }
+    }
+}
+
+/// Lazily parses each serialised `Version` as the iterator advances.
+pub struct ArchivedVersions<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for ArchivedVersions<'a> {
+    type Item = ArchivedVersion<'a>;
+
+    fn next(&mut self) -> Option<ArchivedVersion<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let version = ArchivedVersion {
+            buffer: self.buffer,
+            offset: self.offset,
+        };
+        let (_, offset) = read_u64(self.buffer, self.offset).expect("validated in `ArchivedData::new`");
+        let (_, offset) = read_fixed(self.buffer, offset, 32).expect("validated in `ArchivedData::new`");
+        let (_, offset) = read_bytes(self.buffer, offset).expect("validated in `ArchivedData::new`");
+        self.offset = offset;
+        self.remaining -= 1;
+        Some(version)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}